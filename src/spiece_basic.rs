@@ -14,10 +14,15 @@ use hashbrown::HashMap as BrownHashMap;
 use std::fs::File;
 use protobuf::parse_from_bytes;
 use rust_tokenizers::preprocessing::vocab::sentencepiece_proto::sentencepiece_model::ModelProto;
+use rust_tokenizers::preprocessing::vocab::sentencepiece_proto::sentencepiece_model::TrainerSpec_ModelType;
 use std::io::Read;
 use itertools::Itertools;
 use std::time::Instant;
 use std::collections::HashMap;
+use std::collections::BinaryHeap;
+use std::cmp::Ordering;
+use rand::Rng;
+use yada::DoubleArray;
 
 #[derive(Clone, Copy)]
 pub struct Node<'a> {
@@ -28,47 +33,416 @@ pub struct Node<'a> {
     pub end: usize,
 }
 
+/// A partial segmentation explored by the backward A* search in `nbest_tokenize`. `pos` is
+/// the frontier position still to be covered (searching back towards 0), `g` is the score
+/// accumulated so far, and `priority` is `g` plus the admissible heuristic (the best Viterbi
+/// score achievable from 0 to `pos`), used to order the max-heap.
+struct NBestHypothesis<'a> {
+    priority: f32,
+    g: f32,
+    pos: usize,
+    pieces: Vec<&'a str>,
+}
+
+impl<'a> PartialEq for NBestHypothesis<'a> {
+    fn eq(&self, other: &Self) -> bool {
+        self.priority == other.priority
+    }
+}
+
+impl<'a> Eq for NBestHypothesis<'a> {}
+
+impl<'a> PartialOrd for NBestHypothesis<'a> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<'a> Ord for NBestHypothesis<'a> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.priority.total_cmp(&other.priority)
+    }
+}
+
 pub struct DagNode {
-    pub text: String,
     pub len: usize,
     pub score: f32,
     pub index: i32,
     pub leaf: bool,
+    pub text_id: Option<u32>,
     pub children: BrownHashMap<char, DagNode>,
 }
 
 impl DagNode {
-    pub fn new(text: String) -> DagNode {
-        let len = text.chars().count();
+    pub fn new(len: usize) -> DagNode {
         DagNode {
-            text,
             len,
             score: 0.0,
             index: 0,
             leaf: false,
+            text_id: None,
             children: BrownHashMap::new(),
         }
     }
 }
 
+/// Deduplicates leaf piece strings across the trie. Interior `DagNode`s no longer carry an
+/// owned prefix string (callers reconstruct any substring they need from the original input
+/// slice instead, as `decode_forward_dag` already does via `char_positions`), but leaves
+/// returned by `common_prefix_search` still need their piece text, so they store a compact id
+/// into this shared table rather than a second owned copy of a string already held elsewhere.
+struct TextInterner {
+    strings: Vec<String>,
+    ids: HashMap<String, u32>,
+}
+
+impl TextInterner {
+    fn new() -> TextInterner {
+        TextInterner { strings: Vec::new(), ids: HashMap::new() }
+    }
+
+    fn intern(&mut self, text: &str) -> u32 {
+        if let Some(&id) = self.ids.get(text) {
+            return id;
+        }
+        let id = self.strings.len() as u32;
+        self.strings.push(text.to_owned());
+        self.ids.insert(text.to_owned(), id);
+        id
+    }
+
+    fn resolve(&self, id: u32) -> &str {
+        &self.strings[id as usize]
+    }
+}
+
+/// Parses a SentencePiece byte-fallback piece such as `<0x4E>` and returns the byte value it
+/// represents, or `None` if `piece` is not of that form.
+fn byte_piece_value(piece: &str) -> Option<u8> {
+    if piece.len() == 6 && piece.starts_with("<0x") && piece.ends_with('>') {
+        u8::from_str_radix(&piece[3..5], 16).ok()
+    } else {
+        None
+    }
+}
+
+/// Double-array trie over the `precompiled_charsmap` blob embedded in the model's
+/// `NormalizerSpec`. The blob layout is a little-endian `u32` byte length for the trie region,
+/// followed by that many bytes of a darts_clone-format double array (the same on-disk format
+/// SentencePiece's C++ implementation emits via the `darts_clone` library), and then a tail
+/// blob of null-terminated replacement strings addressed by the trie's leaf values. `yada` is a
+/// pure-Rust, bit-compatible reimplementation of that format, so we defer the base/check
+/// traversal to it rather than hand-rolling the packed-unit bit layout.
+struct CharsMapTrie {
+    trie: DoubleArray<Vec<u8>>,
+    blob: Vec<u8>,
+}
+
+impl CharsMapTrie {
+    fn parse(data: &[u8]) -> Option<CharsMapTrie> {
+        if data.len() < 4 {
+            return None;
+        }
+        let trie_len = u32::from_le_bytes([data[0], data[1], data[2], data[3]]) as usize;
+        let trie_bytes = data.get(4..4 + trie_len)?.to_vec();
+        let blob = data.get(4 + trie_len..)?.to_vec();
+        let trie = DoubleArray::new(trie_bytes).ok()?;
+        Some(CharsMapTrie { trie, blob })
+    }
+
+    /// Leftmost-longest match of a prefix of `bytes`, returning the number of bytes consumed
+    /// and the replacement string to emit in their place, or `None` if no prefix matches.
+    fn longest_match(&self, bytes: &[u8]) -> Option<(usize, &str)> {
+        let (offset, consumed) = self.trie.common_prefix_search(bytes).last()?;
+        let offset = offset as usize;
+        let end = offset + self.blob.get(offset..)?.iter().position(|&b| b == 0)?;
+        let replacement = std::str::from_utf8(&self.blob[offset..end]).ok()?;
+        Some((consumed, replacement))
+    }
+}
+
+#[cfg(test)]
+mod charsmap_trie_tests {
+    use super::*;
+    use yada::builder::DoubleArrayBuilder;
+
+    /// Packs `entries` into the same `[u32 trie_len][trie bytes][blob]` layout SentencePiece
+    /// embeds as `precompiled_charsmap`, with `key` matched against a leaf holding `replacement`.
+    fn build_charsmap(entries: &[(&str, &str)]) -> Vec<u8> {
+        let mut blob = Vec::new();
+        let mut keyset: Vec<(&[u8], u32)> = Vec::new();
+        for (key, replacement) in entries {
+            let offset = blob.len() as u32;
+            blob.extend_from_slice(replacement.as_bytes());
+            blob.push(0);
+            keyset.push((key.as_bytes(), offset));
+        }
+        keyset.sort_by(|a, b| a.0.cmp(b.0));
+        let trie_bytes = DoubleArrayBuilder::build(&keyset).expect("valid keyset");
+
+        let mut data = Vec::new();
+        data.extend_from_slice(&(trie_bytes.len() as u32).to_le_bytes());
+        data.extend_from_slice(&trie_bytes);
+        data.extend_from_slice(&blob);
+        data
+    }
+
+    #[test]
+    fn round_trips_a_known_charsmap_blob() {
+        let data = build_charsmap(&[("a", "A"), ("ab", "AB"), ("\u{FF21}", "A")]);
+        let trie = CharsMapTrie::parse(&data).expect("parses a valid darts_clone trie");
+
+        assert_eq!(trie.longest_match("a".as_bytes()), Some((1, "A")));
+        assert_eq!(trie.longest_match("abc".as_bytes()), Some((2, "AB")));
+        assert_eq!(trie.longest_match("\u{FF21}z".as_bytes()), Some((3, "A")));
+        assert_eq!(trie.longest_match("z".as_bytes()), None);
+    }
+}
+
+#[cfg(test)]
+mod segmentation_tests {
+    use super::*;
+
+    /// Builds a minimal model over `vocab` (piece text, unigram score), skipping the protobuf
+    /// parsing `from_file` normally does so segmentation logic can be exercised directly.
+    fn model_from_vocab(vocab: &[(&str, f32)], model_type: ModelType, byte_fallback: bool) -> SentencePieceModel {
+        let normalizer = Normalizer {
+            add_dummy_prefix: false,
+            remove_extra_whitespaces: false,
+            escape_whitespace: false,
+            charsmap: None,
+        };
+        let mut model = SentencePieceModel {
+            root: DagNode::new(0),
+            vocab: HashMap::new(),
+            byte_fallback,
+            byte_pieces: BrownHashMap::new(),
+            id_to_piece: Vec::new(),
+            normalizer,
+            model_type,
+            interner: TextInterner::new(),
+        };
+        for (idx, (text, score)) in vocab.iter().enumerate() {
+            model.insert(text, *score, idx as i32);
+            model.vocab.insert((*text).to_owned(), (*score, idx as i32));
+            model.id_to_piece.push((*text).to_owned());
+            if let Some(byte) = byte_piece_value(text) {
+                model.byte_pieces.insert(byte, (idx as i32, (*text).to_owned()));
+            }
+        }
+        model
+    }
+
+    #[test]
+    fn bpe_tokenize_rejects_stale_merge_candidates() {
+        // "bc" has the lower rank, so it merges first (b+c -> "bc"). The candidate queued for
+        // "a"+"b" back when "b" was a standalone symbol is still adjacent afterwards, but its
+        // right side now spans "bc" instead -- accepting it anyway would produce "abc", which
+        // was never in the vocabulary.
+        let model = model_from_vocab(
+            &[("a", 0.0), ("b", 0.0), ("c", 0.0), ("d", 0.0), ("bc", 1.0), ("ab", 2.0)],
+            ModelType::Bpe,
+            false,
+        );
+        assert_eq!(model.tokenize("abcd"), vec!("a".to_owned(), "bc".to_owned(), "d".to_owned()));
+    }
+
+    #[test]
+    fn nbest_tokenize_of_zero_is_empty() {
+        let model = model_from_vocab(&[("a", 0.0), ("b", 0.0)], ModelType::Unigram, false);
+        assert!(model.nbest_tokenize("ab", 0).is_empty());
+    }
+
+    #[test]
+    fn nbest_tokenize_orders_by_descending_score() {
+        // "ab" (score 1.0) beats "a"+"b" (score 0.0 + 0.0), so it must come first.
+        let model = model_from_vocab(
+            &[("a", 0.0), ("b", 0.0), ("ab", 1.0)],
+            ModelType::Unigram,
+            false,
+        );
+        let hypotheses = model.nbest_tokenize("ab", 2);
+        assert_eq!(hypotheses.len(), 2);
+        assert_eq!(hypotheses[0].0, vec!("ab".to_owned()));
+        assert_eq!(hypotheses[1].0, vec!("a".to_owned(), "b".to_owned()));
+        assert!(hypotheses[0].1 > hypotheses[1].1);
+    }
+
+    #[test]
+    fn sample_tokenize_is_stable_without_segmentation_ambiguity() {
+        // Only one piece covers each character, so there is exactly one path through the
+        // lattice regardless of the sampling temperature.
+        let model = model_from_vocab(&[("a", 0.0), ("b", 0.0)], ModelType::Unigram, false);
+        assert_eq!(model.sample_tokenize("ab", 1.0), vec!("a".to_owned(), "b".to_owned()));
+    }
+}
+
+/// Returns the byte length of the UTF-8 character starting with `first_byte`.
+fn utf8_char_len(first_byte: u8) -> usize {
+    if first_byte & 0x80 == 0 {
+        1
+    } else if first_byte & 0xE0 == 0xC0 {
+        2
+    } else if first_byte & 0xF0 == 0xE0 {
+        3
+    } else if first_byte & 0xF8 == 0xF0 {
+        4
+    } else {
+        1
+    }
+}
+
+/// Reproduces SentencePiece's text normalization, driven by the model's `NormalizerSpec`
+/// instead of the crate's previous hardcoded `text.replace(' ', "\u{2581}")`.
+struct Normalizer {
+    add_dummy_prefix: bool,
+    remove_extra_whitespaces: bool,
+    escape_whitespace: bool,
+    charsmap: Option<CharsMapTrie>,
+}
+
+impl Normalizer {
+    fn from_proto(proto: &ModelProto) -> Normalizer {
+        let spec = proto.get_normalizer_spec();
+        Normalizer {
+            add_dummy_prefix: spec.get_add_dummy_prefix(),
+            remove_extra_whitespaces: spec.get_remove_extra_whitespaces(),
+            escape_whitespace: spec.get_escape_whitespaces(),
+            charsmap: CharsMapTrie::parse(spec.get_precompiled_charsmap()),
+        }
+    }
+
+    fn normalize(&self, text: &str) -> String {
+        let mut normalized = String::with_capacity(text.len());
+        let bytes = text.as_bytes();
+        let mut pos = 0;
+        while pos < bytes.len() {
+            if let Some(trie) = &self.charsmap {
+                if let Some((consumed, replacement)) = trie.longest_match(&bytes[pos..]) {
+                    normalized.push_str(replacement);
+                    pos += consumed;
+                    continue;
+                }
+            }
+            let char_len = utf8_char_len(bytes[pos]);
+            normalized.push_str(std::str::from_utf8(&bytes[pos..pos + char_len]).unwrap_or("\u{FFFD}"));
+            pos += char_len;
+        }
+
+        if self.remove_extra_whitespaces {
+            normalized = normalized.split_whitespace().collect::<Vec<_>>().join(" ");
+        }
+        if self.add_dummy_prefix && !normalized.starts_with(' ') {
+            normalized = format!(" {}", normalized);
+        }
+        if self.escape_whitespace {
+            normalized = normalized.replace(' ', "\u{2581}");
+        }
+        normalized
+    }
+}
+
+/// Which encoding algorithm a loaded model uses, read from `trainer_spec.model_type`.
+/// `Unigram` drives the lattice decoders (`decode_forward`/`decode_forward_dag`); `Bpe` drives
+/// `bpe_tokenize` instead, since BPE piece scores are merge ranks rather than log-probabilities
+/// additive along a lattice.
+#[derive(Clone, Copy, PartialEq)]
+enum ModelType {
+    Unigram,
+    Bpe,
+}
+
+impl ModelType {
+    fn from_proto(proto: &ModelProto) -> ModelType {
+        match proto.get_trainer_spec().get_model_type() {
+            TrainerSpec_ModelType::BPE => ModelType::Bpe,
+            _ => ModelType::Unigram,
+        }
+    }
+}
+
+/// A run of the original text still to be considered for merging, kept as a doubly linked
+/// list over `(start, end)` byte offsets into the original text so that merges don't require
+/// re-allocating the merged text.
+struct BpeSymbol {
+    start: usize,
+    end: usize,
+    prev: Option<usize>,
+    next: Option<usize>,
+    /// Bumped every time `start`/`end` changes (i.e. this symbol absorbs a merge), so a
+    /// `Merge` candidate queued against a stale span can be detected even when the indices
+    /// it names are still adjacent.
+    generation: u32,
+}
+
+/// A candidate adjacent-pair merge, ordered so that the lowest-rank (highest-priority) pair
+/// sorts greatest and is popped first from the max-heap `BinaryHeap` used by `bpe_tokenize`.
+struct Merge {
+    rank: f32,
+    left: usize,
+    right: usize,
+    left_generation: u32,
+    right_generation: u32,
+}
+
+impl PartialEq for Merge {
+    fn eq(&self, other: &Self) -> bool {
+        self.rank == other.rank && self.left == other.left
+    }
+}
+
+impl Eq for Merge {}
+
+impl PartialOrd for Merge {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Merge {
+    fn cmp(&self, other: &Self) -> Ordering {
+        other.rank.total_cmp(&self.rank).then_with(|| other.left.cmp(&self.left))
+    }
+}
+
 pub struct SentencePieceModel {
     pub vocab: HashMap<String, (f32, i32)>,
-    pub root: DagNode
+    pub root: DagNode,
+    pub byte_fallback: bool,
+    byte_pieces: BrownHashMap<u8, (i32, String)>,
+    id_to_piece: Vec<String>,
+    normalizer: Normalizer,
+    model_type: ModelType,
+    interner: TextInterner,
 }
 
 impl SentencePieceModel {
     pub fn from_file(path: &str) -> SentencePieceModel {
+        Self::from_file_with_byte_fallback(path, None)
+    }
+
+    /// Like `from_file`, but allows overriding whether out-of-vocabulary characters fall back
+    /// to per-byte `<0xNN>` pieces. When `byte_fallback` is `None`, the flag is read from the
+    /// model's `trainer_spec.byte_fallback`, matching upstream SentencePiece behaviour.
+    pub fn from_file_with_byte_fallback(path: &str, byte_fallback: Option<bool>) -> SentencePieceModel {
         let mut f = File::open(path).unwrap();
         let mut contents = Vec::new();
         f.read_to_end(&mut contents).unwrap();
 
         let proto = parse_from_bytes::<ModelProto>(contents.as_slice()).unwrap();
         let vocab = HashMap::new();
-        let root = DagNode::new("".to_string());
-        let mut model = SentencePieceModel { root, vocab };
+        let root = DagNode::new(0);
+        let byte_fallback = byte_fallback.unwrap_or_else(|| proto.get_trainer_spec().get_byte_fallback());
+        let normalizer = Normalizer::from_proto(&proto);
+        let model_type = ModelType::from_proto(&proto);
+        let mut model = SentencePieceModel { root, vocab, byte_fallback, byte_pieces: BrownHashMap::new(), id_to_piece: Vec::new(), normalizer, model_type, interner: TextInterner::new() };
         for (idx, piece) in proto.get_pieces().iter().enumerate() {
             model.insert(piece.get_piece(), piece.get_score(), idx as i32);
             model.vocab.insert(piece.get_piece().to_owned(), (piece.get_score(), idx as i32));
+            model.id_to_piece.push(piece.get_piece().to_owned());
+            if let Some(byte) = byte_piece_value(piece.get_piece()) {
+                model.byte_pieces.insert(byte, (idx as i32, piece.get_piece().to_owned()));
+            }
         }
         model
 
@@ -79,17 +453,12 @@ impl SentencePieceModel {
         let mut node = &mut self.root;
 
         for (idx, character) in word.chars().enumerate() {
-            if !node.children.contains_key(&character) {
-                let mut text = node.text.clone();
-                text.push(character);
-                let new_node = DagNode::new(text);
-                node.children.insert(character, new_node);
-            }
-            node = node.children.get_mut(&character).unwrap();
+            node = node.children.entry(character).or_insert_with(|| DagNode::new(idx + 1));
             if idx == char_count - 1 {
                 node.leaf = true;
                 node.score = score;
                 node.index = index;
+                node.text_id = Some(self.interner.intern(word));
             }
         }
     }
@@ -122,10 +491,11 @@ impl SentencePieceModel {
                 }
             }
             if scores[char_end] <= std::f32::MIN {
+                let unk_text = &text[char_positions[char_end - 1]..char_positions[char_end]];
                 results[char_end] = Some(Node {
-                    text: &text[char_positions[char_end - 1]..char_positions[char_end]],
+                    text: unk_text,
                     score: std::f32::MIN,
-                    index: 0,
+                    index: self.byte_fallback_index(unk_text),
                     start: char_end - 1,
                     end: char_end,
                 });
@@ -174,6 +544,13 @@ impl SentencePieceModel {
         results
     }
 
+    /// Resolves the piece text of a leaf `DagNode` returned by `common_prefix_search`, looking
+    /// it up in the shared interner rather than reading it off the node itself. Returns `None`
+    /// for a non-leaf node, which carries no `text_id`.
+    pub fn node_text(&self, node: &DagNode) -> Option<&str> {
+        node.text_id.map(|id| self.interner.resolve(id))
+    }
+
     pub fn decode_forward_dag<'a>(&'a self, text: &'a str) -> Vec<Option<Node<'a>>> {
         let mut char_positions = text
             .char_indices()
@@ -201,10 +578,11 @@ impl SentencePieceModel {
                 }
             }
             if scores[char_start + 1] <= std::f32::MIN {
+                let unk_text = &text[char_positions[char_start]..char_positions[char_start + 1]];
                 results[char_start + 1] = Some(Node {
-                    text: &text[char_positions[char_start]..char_positions[char_start + 1]],
+                    text: unk_text,
                     score: std::f32::MIN,
-                    index: 0,
+                    index: self.byte_fallback_index(unk_text),
                     start: char_start,
                     end: char_start + 1,
                 });
@@ -214,20 +592,350 @@ impl SentencePieceModel {
         results
     }
 
+    /// Returns the sentinel index used for an out-of-vocabulary span: `-1` (expanded by
+    /// `node_to_pieces` into one `<0xNN>` piece per UTF-8 byte) when byte fallback is enabled
+    /// and every byte of `unk_text` has a corresponding piece, or `0` otherwise, mirroring the
+    /// previous behaviour of silently mapping unknown text to token id 0.
+    fn byte_fallback_index(&self, unk_text: &str) -> i32 {
+        if self.byte_fallback && unk_text.bytes().all(|byte| self.byte_pieces.contains_key(&byte)) {
+            -1
+        } else {
+            0
+        }
+    }
+
+    /// Expands a decoded node into its output token ids: a single id normally, or one
+    /// `<0xNN>` piece id per UTF-8 byte when the node is a byte-fallback sentinel.
+    fn node_to_ids(&self, node: &Node) -> Vec<i32> {
+        if node.index == -1 {
+            node.text.bytes().map(|byte| self.byte_pieces.get(&byte).unwrap().0).collect()
+        } else {
+            vec!(node.index)
+        }
+    }
+
+    /// Expands a decoded node into its output piece strings: a single piece normally, or one
+    /// `<0xNN>` piece per UTF-8 byte when the node is a byte-fallback sentinel.
+    fn node_to_pieces(&self, node: &Node) -> Vec<String> {
+        if node.index == -1 {
+            node.text.bytes().map(|byte| self.byte_pieces.get(&byte).unwrap().1.clone()).collect()
+        } else {
+            vec!(node.text.to_string())
+        }
+    }
+
+    /// Runs the forward pass under the log-sum-exp (marginal) semiring instead of the
+    /// Viterbi (max) semiring used by `decode_forward_dag`, and returns both the per-position
+    /// log-partition `alpha` values and, for every position, the incoming edges that can reach
+    /// it. This is the lattice needed to sample a segmentation instead of just decoding the
+    /// single best one, mirroring SentencePiece's subword regularization.
+    fn decode_forward_dag_marginal<'a>(&'a self, text: &'a str) -> (Vec<f32>, Vec<Vec<Node<'a>>>) {
+        let mut char_positions = text
+            .char_indices()
+            .map(|(pos, _)| pos)
+            .collect_vec();
+        char_positions.push(text.len());
+        let mut alphas = vec!(std::f32::NEG_INFINITY; char_positions.len());
+        let mut edges: Vec<Vec<Node>> = vec!(vec!(); char_positions.len());
+        alphas[0] = 0f32;
+
+        for char_start in 0..char_positions.len() - 1 {
+            let matches = self.common_prefix_search(&text[char_positions[char_start]..]);
+            for node in matches {
+                let char_end = char_start + node.len;
+                edges[char_end].push(Node {
+                    text: &text[char_positions[char_start]..char_positions[char_end]],
+                    score: node.score,
+                    index: node.index,
+                    start: char_start,
+                    end: char_end,
+                });
+            }
+        }
+        for char_end in 1..char_positions.len() {
+            if edges[char_end].is_empty() {
+                let unk_text = &text[char_positions[char_end - 1]..char_positions[char_end]];
+                edges[char_end].push(Node {
+                    text: unk_text,
+                    score: std::f32::MIN,
+                    index: self.byte_fallback_index(unk_text),
+                    start: char_end - 1,
+                    end: char_end,
+                });
+            }
+            let max_incoming = edges[char_end].iter()
+                .map(|edge| alphas[edge.start] + edge.score)
+                .fold(std::f32::NEG_INFINITY, f32::max);
+            let sum_exp: f32 = edges[char_end].iter()
+                .map(|edge| (alphas[edge.start] + edge.score - max_incoming).exp())
+                .sum();
+            alphas[char_end] = max_incoming + sum_exp.ln();
+        }
+        (alphas, edges)
+    }
+
+    /// Draws a stochastic segmentation from the lattice instead of the single best path,
+    /// matching SentencePiece's subword regularization (`--enable_sampling`). At each
+    /// position, incoming edges are weighted by `exp(alpha[start] + score * alpha_temp -
+    /// alpha[end])`, where `alpha_temp` is the sampling temperature (higher values sharpen
+    /// the distribution towards the Viterbi path), then one edge is drawn and the walk
+    /// continues backward from its start position.
+    pub fn sample_tokenize(&self, text: &str, alpha_temp: f32) -> Vec<String> {
+        let text = self.normalize(text);
+        let text = text.as_str();
+        let (alphas, edges) = self.decode_forward_dag_marginal(text);
+
+        let mut rng = rand::thread_rng();
+        let mut pieces = vec!();
+        let mut end = alphas.len() - 1;
+        while end > 0 {
+            let incoming = &edges[end];
+            let weights = incoming.iter()
+                .map(|edge| alphas[edge.start] + edge.score * alpha_temp - alphas[end])
+                .collect_vec();
+            let max_weight = weights.iter().cloned().fold(std::f32::NEG_INFINITY, f32::max);
+            let exp_weights = weights.iter().map(|weight| (weight - max_weight).exp()).collect_vec();
+            let total: f32 = exp_weights.iter().sum();
+
+            let mut draw = rng.gen::<f32>() * total;
+            let mut chosen = exp_weights.len() - 1;
+            for (idx, weight) in exp_weights.iter().enumerate() {
+                if draw < *weight {
+                    chosen = idx;
+                    break;
+                }
+                draw -= *weight;
+            }
+
+            let edge = &incoming[chosen];
+            pieces.push(edge.text.to_string());
+            end = edge.start;
+        }
+        pieces.reverse();
+        pieces
+    }
+
+    /// Runs the forward Viterbi DP like `decode_forward_dag`, but in addition to the best
+    /// score reaching each position, also keeps every incoming edge (not just the best one).
+    /// The scores serve as the admissible heuristic and the edges as the search graph for the
+    /// backward A* in `nbest_tokenize`.
+    fn decode_forward_dag_with_edges<'a>(&'a self, text: &'a str) -> (Vec<f32>, Vec<Vec<Node<'a>>>) {
+        let mut char_positions = text
+            .char_indices()
+            .map(|(pos, _)| pos)
+            .collect_vec();
+        char_positions.push(text.len());
+        let mut scores = vec!(std::f32::NEG_INFINITY; char_positions.len());
+        let mut edges: Vec<Vec<Node>> = vec!(vec!(); char_positions.len());
+        scores[0] = 0f32;
+
+        for char_start in 0..char_positions.len() - 1 {
+            let matches = self.common_prefix_search(&text[char_positions[char_start]..]);
+            for node in matches {
+                let char_end = char_start + node.len;
+                edges[char_end].push(Node {
+                    text: &text[char_positions[char_start]..char_positions[char_end]],
+                    score: node.score,
+                    index: node.index,
+                    start: char_start,
+                    end: char_end,
+                });
+            }
+        }
+        for char_end in 1..char_positions.len() {
+            if edges[char_end].is_empty() {
+                let unk_text = &text[char_positions[char_end - 1]..char_positions[char_end]];
+                edges[char_end].push(Node {
+                    text: unk_text,
+                    score: std::f32::MIN,
+                    index: self.byte_fallback_index(unk_text),
+                    start: char_end - 1,
+                    end: char_end,
+                });
+            }
+            scores[char_end] = edges[char_end].iter()
+                .map(|edge| scores[edge.start] + edge.score)
+                .fold(std::f32::NEG_INFINITY, f32::max);
+        }
+        (scores, edges)
+    }
+
+    /// Enumerates the top-`n` distinct segmentations with their total scores, using the
+    /// standard Forward-DP + Backward-A* approach from SentencePiece. `scores[pos]` (the
+    /// best achievable score from 0 to `pos`) is an admissible heuristic for the remaining
+    /// distance to the start, so expanding hypotheses in order of `g + h` guarantees
+    /// segmentations pop from the heap in decreasing order of total score.
+    pub fn nbest_tokenize(&self, text: &str, n: usize) -> Vec<(Vec<String>, f32)> {
+        if n == 0 {
+            return vec!();
+        }
+        let text = self.normalize(text);
+        let text = text.as_str();
+        let (scores, edges) = self.decode_forward_dag_with_edges(text);
+
+        let last = scores.len() - 1;
+        let mut heap = BinaryHeap::new();
+        heap.push(NBestHypothesis { priority: scores[last], g: 0f32, pos: last, pieces: vec!() });
+
+        let mut results = vec!();
+        while let Some(hypothesis) = heap.pop() {
+            if hypothesis.pos == 0 {
+                let mut pieces: Vec<String> = hypothesis.pieces.iter().map(|piece| piece.to_string()).collect();
+                pieces.reverse();
+                results.push((pieces, hypothesis.g));
+                if results.len() == n {
+                    break;
+                }
+                continue;
+            }
+            for edge in &edges[hypothesis.pos] {
+                let mut pieces = hypothesis.pieces.clone();
+                pieces.push(edge.text);
+                let g = hypothesis.g + edge.score;
+                let h = scores[edge.start];
+                heap.push(NBestHypothesis { priority: g + h, g, pos: edge.start, pieces });
+            }
+        }
+        results
+    }
+
+    /// Normalizes `text` per the model's `NormalizerSpec`: precompiled-charsmap substitution,
+    /// whitespace collapsing, dummy-prefix insertion and whitespace escaping, in that order.
+    pub fn normalize(&self, text: &str) -> String {
+        self.normalizer.normalize(text)
+    }
+
+    /// Merges adjacent symbols by ascending merge rank (the piece `score`) until no mergeable
+    /// pair remains, the BPE encoding used by `BPE`-type models instead of the Unigram
+    /// lattice. Candidate merges are kept in a max-heap ordered by rank so the best available
+    /// merge is always applied next; stale candidates (whose symbols were already merged
+    /// elsewhere, or whose span grew via a sibling merge while staying adjacent) are detected
+    /// via the `removed` flags and a per-symbol `generation` counter and simply skipped.
+    fn bpe_tokenize<'a>(&self, text: &'a str) -> Vec<&'a str> {
+        let char_bounds = text.char_indices().map(|(pos, _)| pos).chain(std::iter::once(text.len())).collect_vec();
+        let symbol_count = char_bounds.len() - 1;
+        if symbol_count == 0 {
+            return vec!();
+        }
+        let mut symbols: Vec<BpeSymbol> = (0..symbol_count).map(|i| BpeSymbol {
+            start: char_bounds[i],
+            end: char_bounds[i + 1],
+            prev: if i == 0 { None } else { Some(i - 1) },
+            next: if i + 1 == symbol_count { None } else { Some(i + 1) },
+            generation: 0,
+        }).collect();
+        let mut removed = vec!(false; symbol_count);
+
+        let mut heap = BinaryHeap::new();
+        let push_candidate = |heap: &mut BinaryHeap<Merge>, symbols: &[BpeSymbol], left: usize| {
+            if let Some(right) = symbols[left].next {
+                let pair = &text[symbols[left].start..symbols[right].end];
+                if let Some((rank, _)) = self.vocab.get(pair) {
+                    heap.push(Merge {
+                        rank: *rank,
+                        left,
+                        right,
+                        left_generation: symbols[left].generation,
+                        right_generation: symbols[right].generation,
+                    });
+                }
+            }
+        };
+        for symbol in 0..symbol_count {
+            push_candidate(&mut heap, &symbols, symbol);
+        }
+
+        while let Some(merge) = heap.pop() {
+            if removed[merge.left] || removed[merge.right]
+                || symbols[merge.left].next != Some(merge.right)
+                || symbols[merge.left].generation != merge.left_generation
+                || symbols[merge.right].generation != merge.right_generation {
+                continue;
+            }
+            symbols[merge.left].end = symbols[merge.right].end;
+            symbols[merge.left].generation += 1;
+            let right_next = symbols[merge.right].next;
+            symbols[merge.left].next = right_next;
+            if let Some(next) = right_next {
+                symbols[next].prev = Some(merge.left);
+            }
+            removed[merge.right] = true;
+
+            if let Some(prev) = symbols[merge.left].prev {
+                push_candidate(&mut heap, &symbols, prev);
+            }
+            push_candidate(&mut heap, &symbols, merge.left);
+        }
+
+        let mut pieces = vec!();
+        let mut cursor = Some(0);
+        while let Some(symbol) = cursor {
+            pieces.push(&text[symbols[symbol].start..symbols[symbol].end]);
+            cursor = symbols[symbol].next;
+        }
+        pieces
+    }
+
     pub fn tokenize(&self, text: &str) -> Vec<String> {
-        let text = text.replace(' ', "\u{2581}");
+        let text = self.normalize(text);
         let text = text.as_str();
+        if self.model_type == ModelType::Bpe {
+            return self.bpe_tokenize(text).into_iter().map(|piece| piece.to_string()).collect();
+        }
         let output = self.decode_forward(text);
         let decoded = self.decode_backward(&output);
-        decoded.into_iter().map(|node| node.text.to_string()).collect()
+        decoded.into_iter().flat_map(|node| self.node_to_pieces(node)).collect()
     }
 
     pub fn tokenize_dag(&self, text: &str) -> Vec<String> {
-        let text = text.replace(' ', "\u{2581}");
+        let text = self.normalize(text);
         let text = text.as_str();
+        if self.model_type == ModelType::Bpe {
+            return self.bpe_tokenize(text).into_iter().map(|piece| piece.to_string()).collect();
+        }
         let output = self.decode_forward_dag(text);
         let decoded = self.decode_backward(&output);
-        decoded.into_iter().map(|node| node.text.to_string()).collect()
+        decoded.into_iter().flat_map(|node| self.node_to_pieces(node)).collect()
+    }
+
+    /// Like `tokenize`, but returns each matched piece's vocabulary id instead of its text,
+    /// which is what model input pipelines actually consume.
+    pub fn tokenize_to_ids(&self, text: &str) -> Vec<i32> {
+        let text = self.normalize(text);
+        let text = text.as_str();
+        if self.model_type == ModelType::Bpe {
+            return self.bpe_tokenize(text).into_iter().map(|piece| self.vocab.get(piece).map(|entry| entry.1).unwrap_or(0)).collect();
+        }
+        let output = self.decode_forward(text);
+        let decoded = self.decode_backward(&output);
+        decoded.into_iter().flat_map(|node| self.node_to_ids(node)).collect()
+    }
+
+    /// Inverse of `tokenize_to_ids`: looks up each id's piece, reassembling consecutive
+    /// `<0xNN>` byte-fallback pieces into the raw UTF-8 bytes they represent (the counterpart
+    /// to `node_to_ids`/`node_to_pieces` expanding an OOV character the other way), then turns
+    /// the `\u{2581}` space marker back into spaces, stripping a single leading marker.
+    pub fn decode(&self, ids: &[i32]) -> String {
+        let mut concatenated = String::new();
+        let mut byte_run: Vec<u8> = Vec::new();
+        for id in ids {
+            let piece = self.id_to_piece.get(*id as usize).map(String::as_str).unwrap_or("");
+            match byte_piece_value(piece) {
+                Some(byte) => byte_run.push(byte),
+                None => {
+                    if !byte_run.is_empty() {
+                        concatenated.push_str(&String::from_utf8_lossy(&byte_run));
+                        byte_run.clear();
+                    }
+                    concatenated.push_str(piece);
+                }
+            }
+        }
+        if !byte_run.is_empty() {
+            concatenated.push_str(&String::from_utf8_lossy(&byte_run));
+        }
+        let concatenated = concatenated.strip_prefix('\u{2581}').unwrap_or(&concatenated).to_owned();
+        concatenated.replace('\u{2581}', " ")
     }
 }
 